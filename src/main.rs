@@ -1,20 +1,34 @@
 use std::{
     collections::{HashMap, HashSet},
-    fs::{copy, create_dir_all},
+    hash::Hasher,
+    io::{IsTerminal, Read, Write},
     num::NonZeroUsize,
     path::{Path, PathBuf},
     process::exit,
-    thread
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use chrono::Local;
 use clap::Parser;
 use cron::Schedule;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::Gitignore;
 use log::log_enabled;
 use rayon::{prelude::*, ThreadPoolBuilder};
+use siphasher::sip128::{Hasher128, SipHasher13};
 use walkdir::WalkDir;
 
+/// Size of the leading block read for the cheap "partial hash" pass.
+const PARTIAL_HASH_SIZE: usize = 4096;
+/// Block size used while streaming a file for the "full hash" pass.
+const FULL_HASH_BLOCK_SIZE: usize = 1024 * 1024;
+
 fn main() {
     let cli = Cli::parse();
     exit(cli.run().map_or_else(
@@ -43,12 +57,65 @@ struct Cli {
 
     #[clap(short, long, parse(try_from_str = parse_cron))]
     cron_expr: Option<Schedule>,
+
+    /// Skip a destination file only when its size and modification time
+    /// indicate it is already up to date with the source.
+    #[clap(short, long)]
+    update: bool,
+
+    /// Re-copy a destination file whenever its content differs from the
+    /// source, verified by a cheap partial hash followed by a full hash.
+    #[clap(long)]
+    checksum: bool,
+
+    /// Only print what would be copied (and, with --mirror, deleted), without
+    /// copying or deleting any file. A missing `to` directory is still created.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// With --dry-run, print the planned destination paths NUL-separated
+    /// (e.g. for piping into `xargs -0`) instead of a human-readable plan.
+    #[clap(short = '0', long)]
+    print0: bool,
+
+    /// Exclude entries whose path matches this glob; may be repeated. A
+    /// matching directory is pruned from the walk entirely.
+    #[clap(long = "exclude")]
+    excludes: Vec<String>,
+
+    /// Only copy files whose path matches this glob; may be repeated.
+    #[clap(long = "include")]
+    includes: Vec<String>,
+
+    /// Skip entries ignored by the nearest `.gitignore` files under each
+    /// `from` root.
+    #[clap(long)]
+    gitignore: bool,
+
+    /// Make `to` an exact mirror of the sources by also deleting
+    /// destination files whose source no longer exists. Respects
+    /// --exclude, so user-managed files under an excluded path are never
+    /// deleted.
+    #[clap(long, alias = "delete")]
+    mirror: bool,
 }
 
 fn parse_cron(s: &str) -> Result<Schedule> {
     s.parse().map_err(Into::into)
 }
 
+/// How an existing destination file is checked against its source to decide
+/// whether it needs to be re-copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompareMode {
+    /// A destination file is considered up to date as soon as it exists.
+    Exists,
+    /// Compare size and modification time.
+    Update,
+    /// Compare size, then a partial hash, then a full hash of the content.
+    Checksum,
+}
+
 impl Cli {
     fn run(&self) -> Result<()> {
         self.check()?;
@@ -81,12 +148,12 @@ impl Cli {
                     self.to.display()
                 );
 
-                try_copy(&self.from, &self.to)?;
+                try_copy(&RealFs, self)?;
 
                 println!("复制完成，用时：{}", Local::now() - start);
             }
         } else {
-            try_copy(&self.from, &self.to)?;
+            try_copy(&RealFs, self)?;
         }
         Ok(())
     }
@@ -102,6 +169,10 @@ impl Cli {
             .filter_module(env!("CARGO_CRATE_NAME"), level)
             .init();
 
+        if self.update && self.checksum {
+            bail!("--update and --checksum cannot be used together");
+        }
+
         if self.from.iter().collect::<HashSet<_>>().len() != self.from.len() {
             bail!("duplicated paths: {:?}", self.from);
         }
@@ -112,7 +183,7 @@ impl Cli {
         }
         if !self.to.exists() {
             log::info!("creating to target path: {}", self.to.display());
-            create_dir_all(&self.to)?;
+            std::fs::create_dir_all(&self.to)?;
         } else if !self.to.is_dir() {
             bail!(
                 "directory {} does not exist, please create a directory",
@@ -121,22 +192,131 @@ impl Cli {
         }
         Ok(())
     }
+
+    fn compare_mode(&self) -> CompareMode {
+        if self.checksum {
+            CompareMode::Checksum
+        } else if self.update {
+            CompareMode::Update
+        } else {
+            CompareMode::Exists
+        }
+    }
+}
+
+/// The filesystem operations `try_copy` needs, abstracted so it can run
+/// against a real disk (`RealFs`) or an in-memory tree (`FakeFs`) in tests.
+trait Fs: Send + Sync {
+    /// List the files under `root` that `matcher` keeps.
+    fn walk(&self, root: &Path, matcher: &PathMatcher) -> Result<Vec<PathBuf>>;
+    /// Copy `from` to `to`, leaving no partial file at `to` on failure.
+    fn copy(&self, from: &Path, to: &Path) -> Result<()>;
+    fn create_dir_all(&self, path: &Path) -> Result<()>;
+    fn metadata(&self, path: &Path) -> Result<Meta>;
+    fn exists(&self, path: &Path) -> bool;
+    fn remove_file(&self, path: &Path) -> Result<()>;
+    /// Open `path` for reading, used to compute partial/full hashes.
+    fn open(&self, path: &Path) -> Result<Box<dyn Read>>;
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf>;
+}
+
+/// Metadata common to `Fs` implementations; `std::fs::Metadata` can't be
+/// constructed by `FakeFs`, so this is the subset `try_copy` actually needs.
+#[derive(Debug, Clone, Copy)]
+struct Meta {
+    len: u64,
+    modified: Option<SystemTime>,
 }
 
-fn try_copy<P: AsRef<Path>>(from: &[P], to: &P) -> Result<()> {
+/// `Fs` backed by `std::fs` and `walkdir`.
+struct RealFs;
+
+impl Fs for RealFs {
+    fn walk(&self, root: &Path, matcher: &PathMatcher) -> Result<Vec<PathBuf>> {
+        WalkDir::new(root)
+            .into_iter()
+            .filter_entry(|e| matcher.is_included(e.path(), e.file_type().is_dir()))
+            .par_bridge()
+            .map(|dir| dir.map(|p| p.path().to_path_buf()))
+            .filter(|dir| dir.as_ref().map_or(true, |en| en.is_file()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Into::into)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        // Copy into a sibling temp file and rename onto `to` so a kill mid-copy
+        // (or an overlapping run) never leaves a truncated file at `to`: rename
+        // within the same filesystem is atomic.
+        let tmp = tmp_path(to);
+        std::fs::copy(from, &tmp).inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp);
+        })?;
+        std::fs::rename(&tmp, to).inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp);
+        })?;
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        std::fs::create_dir_all(path).map_err(Into::into)
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Meta> {
+        let meta = path.metadata()?;
+        Ok(Meta {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        })
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        std::fs::remove_file(path).map_err(Into::into)
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Read>> {
+        Ok(Box::new(std::fs::File::open(path)?))
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf> {
+        path.canonicalize().map_err(Into::into)
+    }
+}
+
+/// Build a sibling temporary path for `to`, e.g. `dir/.name.tmp-<rand>`.
+fn tmp_path(to: &Path) -> PathBuf {
+    let name = to
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    let tmp_name = format!(".{}.tmp-{:x}", name, rand::random::<u64>());
+    to.with_file_name(tmp_name)
+}
+
+fn try_copy(fs: &dyn Fs, cli: &Cli) -> Result<()> {
+    let mode = cli.compare_mode();
     let (from, to) = (
-        from.iter()
-            .map(|p| p.as_ref().canonicalize())
+        cli.from
+            .iter()
+            .map(|p| fs.canonicalize(p))
             .collect::<Result<Vec<_>, _>>()?,
-        to.as_ref().canonicalize()?,
+        fs.canonicalize(&cli.to)?,
     );
     log::trace!("try copy from {:?} to {}", from, to.display());
 
+    let excludes = build_globset(&cli.excludes)?;
+    let includes = build_globset(&cli.includes)?;
+
     // find all items in from and to folders
     let from_items = from
         .iter()
         .filter_map(|p| {
-            walk_items(p)
+            let gitignores = if cli.gitignore { load_gitignore(p) } else { vec![] };
+            let matcher = PathMatcher::new(p.clone(), excludes.clone(), includes.clone(), gitignores);
+            fs.walk(p, &matcher)
                 .map(|paths| (p.to_path_buf(), paths))
                 .map_err(|e| log::warn!("failed to walk path `{}`: {}", p.display(), e))
                 .ok()
@@ -150,7 +330,7 @@ fn try_copy<P: AsRef<Path>>(from: &[P], to: &P) -> Result<()> {
     if log_enabled!(log::Level::Info) {
         let size = froms
             .iter()
-            .flat_map(|p| p.metadata().map(|data| data.len()))
+            .flat_map(|p| fs.metadata(p).map(|data| data.len))
             .sum::<u64>();
         log::info!(
             "found {} items in from: {:?}. size: {}MB",
@@ -160,75 +340,428 @@ fn try_copy<P: AsRef<Path>>(from: &[P], to: &P) -> Result<()> {
         );
     }
 
-    let to_items = walk_items(&to)?;
+    let to_matcher = PathMatcher::new(to.clone(), excludes.clone(), GlobSet::empty(), vec![]);
+    let to_items = fs.walk(&to, &to_matcher)?;
     log::debug!("found {} items in to: {}", to_items.len(), to.display());
 
-    // compare from and find items that dont exist in to
-    let from_tos = from_items
+    // compare from and to, keeping only items that are missing or stale
+    let candidates = from_items
         .iter()
         // get pair of base,from_item
         .flat_map(|(base, items)| items.iter().map(move |item| (base, item)))
         // get to_item
         .map(|(base, from)| {
             from.strip_prefix(base)
-                .map(|suffix| (from, to.join(suffix)))
-        })
-        // filter items
-        .filter(|res| {
-            res.as_ref()
-                .map_or(true, |(_, to_target)| !to_items.contains(to_target))
+                .map(|suffix| (from.clone(), to.join(suffix)))
         })
         .collect::<Result<Vec<_>, _>>()?;
 
-    if log_enabled!(log::Level::Info) {
-        let len = from_tos
-            .iter()
-            .flat_map(|(from, _)| from.metadata().map(|data| data.len()))
-            .sum::<u64>();
-        log::info!(
-            "trying parallel copy {} items {}MB from `{:?}` to {}",
-            from_tos.len(),
-            len as f64 / (1024 * 1024) as f64,
-            from,
-            to.display()
+    let to_set = to_items.iter().map(PathBuf::as_path).collect::<HashSet<_>>();
+    let from_tos = candidates
+        .into_par_iter()
+        .filter(|(from, to_target)| needs_copy(fs, from, to_target, &to_set, mode))
+        .collect::<Vec<_>>();
+
+    let sizes = from_tos
+        .iter()
+        .map(|(from, _)| fs.metadata(from).map(|data| data.len).unwrap_or(0))
+        .collect::<Vec<_>>();
+    let total_bytes = sizes.iter().sum::<u64>();
+    log::info!(
+        "trying parallel copy {} items {}MB from `{:?}` to {}",
+        from_tos.len(),
+        total_bytes as f64 / (1024 * 1024) as f64,
+        from,
+        to.display()
+    );
+
+    let orphans = if cli.mirror {
+        find_orphans(fs, &to_items, &to, &from)
+    } else {
+        vec![]
+    };
+
+    if cli.dry_run {
+        print_plan(&from_tos, total_bytes, cli.print0);
+        if cli.mirror {
+            print_deletions(&orphans, cli.print0);
+        }
+        return Ok(());
+    }
+
+    if cli.mirror {
+        delete_orphans(fs, &orphans)?;
+    }
+
+    let progress = Arc::new(Progress::default());
+    let reporter = ProgressReporter::spawn(progress.clone(), from_tos.len(), total_bytes);
+
+    let result = from_tos
+        .par_iter()
+        .zip(sizes.par_iter())
+        .try_for_each(|((from, to), &len)| {
+            if let Some(p) = to.parent().filter(|p| !fs.exists(p)) {
+                log::debug!("creating directories {} for {}", p.display(), to.display());
+                fs.create_dir_all(p)?;
+            }
+            log::trace!("copying from `{}` to `{}`", from.display(), to.display());
+            fs.copy(from, to)?;
+            progress.add(len);
+            Ok(())
+        });
+
+    reporter.finish(&progress, from_tos.len(), total_bytes);
+    result
+}
+
+/// Copied-file and copied-byte counters updated from the parallel copy
+/// closure, sampled by `ProgressReporter` without contending with the
+/// copy workers.
+#[derive(Default)]
+struct Progress {
+    files: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Progress {
+    fn add(&self, bytes: u64) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// The last `report_progress` sample, kept so the next tick can compute the
+/// throughput since that sample instead of since the copy started.
+type LastSample = Mutex<(Instant, u64)>;
+
+/// Periodically renders `Progress` while a copy is in flight: a single
+/// rewriting status line on a TTY, periodic log lines otherwise.
+struct ProgressReporter {
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+    last: Arc<LastSample>,
+    is_tty: bool,
+}
+
+impl ProgressReporter {
+    fn spawn(progress: Arc<Progress>, total_files: usize, total_bytes: u64) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let is_tty = std::io::stdout().is_terminal();
+        let last = Arc::new(Mutex::new((Instant::now(), 0)));
+        let (stop_bg, last_bg) = (stop.clone(), last.clone());
+        let handle = thread::spawn(move || {
+            while !stop_bg.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(200));
+                report_progress(&progress, total_files, total_bytes, &last_bg, is_tty);
+            }
+        });
+        Self {
+            stop,
+            handle: Some(handle),
+            last,
+            is_tty,
+        }
+    }
+
+    fn finish(mut self, progress: &Progress, total_files: usize, total_bytes: u64) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        report_progress(progress, total_files, total_bytes, &self.last, self.is_tty);
+        if self.is_tty {
+            println!();
+        }
+    }
+}
+
+/// Render one progress line, with `MB/s` computed since the last sample (not
+/// the cumulative average since the copy started) so the figure reflects
+/// current throughput even as the rate changes over a long run.
+fn report_progress(
+    progress: &Progress,
+    total_files: usize,
+    total_bytes: u64,
+    last: &LastSample,
+    is_tty: bool,
+) {
+    let files = progress.files.load(Ordering::Relaxed);
+    let bytes = progress.bytes.load(Ordering::Relaxed);
+
+    let now = Instant::now();
+    let (prev_time, prev_bytes) = std::mem::replace(&mut *last.lock().unwrap(), (now, bytes));
+    let elapsed = now.duration_since(prev_time);
+    let mbps = bytes.saturating_sub(prev_bytes) as f64
+        / (1024 * 1024) as f64
+        / elapsed.as_secs_f64().max(f64::EPSILON);
+
+    let line = format!(
+        "copied {}/{} files, {:.1}/{:.1}MB, {:.1}MB/s",
+        files,
+        total_files,
+        bytes as f64 / (1024 * 1024) as f64,
+        total_bytes as f64 / (1024 * 1024) as f64,
+        mbps
+    );
+    if is_tty {
+        print!("\r{}", line);
+        let _ = std::io::stdout().flush();
+    } else {
+        log::info!("{}", line);
+    }
+}
+
+/// Print the planned `(from, to)` copy actions instead of performing them.
+///
+/// With `print0`, only the destination paths are printed, NUL-separated, so
+/// the plan can be piped into `xargs -0` or similar tooling without
+/// breaking on paths containing spaces or newlines.
+fn print_plan(from_tos: &[(PathBuf, PathBuf)], total_bytes: u64, print0: bool) {
+    if print0 {
+        for (_, to) in from_tos {
+            print!("{}\0", to.display());
+        }
+        return;
+    }
+    for (from, to) in from_tos {
+        println!("{} -> {}", from.display(), to.display());
+    }
+    println!(
+        "would copy {} items, {}MB",
+        from_tos.len(),
+        total_bytes as f64 / (1024 * 1024) as f64
+    );
+}
+
+/// Find destination files whose corresponding source no longer exists
+/// under any `from` base, i.e. files `--mirror` would delete.
+fn find_orphans(fs: &dyn Fs, to_items: &[PathBuf], to: &Path, from: &[PathBuf]) -> Vec<PathBuf> {
+    to_items
+        .iter()
+        .filter(|to_item| {
+            // A `to_item` that isn't under `to` can't be localized to a source
+            // path, so it must never be treated as an orphan to delete.
+            to_item.strip_prefix(to).is_ok_and(|suffix| {
+                !from.iter().any(|base| fs.exists(&base.join(suffix)))
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+fn delete_orphans(fs: &dyn Fs, orphans: &[PathBuf]) -> Result<()> {
+    orphans.par_iter().try_for_each(|p| {
+        log::info!("deleting orphaned destination file {}", p.display());
+        fs.remove_file(p)
+    })
+}
+
+/// Print the destination files that `--mirror` would delete.
+fn print_deletions(orphans: &[PathBuf], print0: bool) {
+    if print0 {
+        for p in orphans {
+            print!("{}\0", p.display());
+        }
+        return;
+    }
+    for p in orphans {
+        println!("delete {}", p.display());
+    }
+    println!("would delete {} orphaned items", orphans.len());
+}
+
+/// Decide whether `from` needs to be (re-)copied to `to`.
+///
+/// When `to` does not exist the file is always copied. Otherwise the
+/// decision depends on `mode`: `Exists` never re-copies, `Update` compares
+/// size and modification time, and `Checksum` compares size, then a partial
+/// hash of the first block, and only on a collision a full streaming hash -
+/// each stage short-circuits as soon as a difference is found.
+fn needs_copy(fs: &dyn Fs, from: &Path, to: &Path, to_items: &HashSet<&Path>, mode: CompareMode) -> bool {
+    if !to_items.contains(to) {
+        return true;
+    }
+    match mode {
+        CompareMode::Exists => false,
+        CompareMode::Update | CompareMode::Checksum => {
+            files_differ(fs, from, to, mode).unwrap_or_else(|e| {
+                log::warn!(
+                    "failed to compare `{}` with `{}`, will recopy: {}",
+                    from.display(),
+                    to.display(),
+                    e
+                );
+                true
+            })
+        }
+    }
+}
+
+/// Per-file information gathered while comparing a source and destination
+/// file, cheapest checks first.
+#[derive(Debug, Default)]
+struct Fileinfo {
+    len: u64,
+    partial_hash: Option<u128>,
+    full_hash: Option<u128>,
+}
+
+fn files_differ(fs: &dyn Fs, from: &Path, to: &Path, mode: CompareMode) -> Result<bool> {
+    let (from_meta, to_meta) = (fs.metadata(from)?, fs.metadata(to)?);
+    let mut from_info = Fileinfo {
+        len: from_meta.len,
+        ..Default::default()
+    };
+    let mut to_info = Fileinfo {
+        len: to_meta.len,
+        ..Default::default()
+    };
+    if from_info.len != to_info.len {
+        return Ok(true);
+    }
+
+    if mode == CompareMode::Update {
+        let (from_modified, to_modified) = (
+            from_meta
+                .modified
+                .ok_or_else(|| anyhow!("modification time not available for `{}`", from.display()))?,
+            to_meta
+                .modified
+                .ok_or_else(|| anyhow!("modification time not available for `{}`", to.display()))?,
         );
+        return Ok(from_modified > to_modified);
+    }
+
+    from_info.partial_hash = Some(partial_hash(fs, from)?);
+    to_info.partial_hash = Some(partial_hash(fs, to)?);
+    if from_info.partial_hash != to_info.partial_hash {
+        return Ok(true);
+    }
+
+    from_info.full_hash = Some(full_hash(fs, from)?);
+    to_info.full_hash = Some(full_hash(fs, to)?);
+    Ok(from_info.full_hash != to_info.full_hash)
+}
+
+/// Hash only the first `PARTIAL_HASH_SIZE` bytes of `path`.
+fn partial_hash(fs: &dyn Fs, path: &Path) -> Result<u128> {
+    let mut reader = fs.open(path)?;
+    let mut buf = [0u8; PARTIAL_HASH_SIZE];
+    let n = reader.read(&mut buf)?;
+    Ok(sip_hash128(&buf[..n]))
+}
+
+/// Hash the whole file content, streamed in fixed-size blocks.
+fn full_hash(fs: &dyn Fs, path: &Path) -> Result<u128> {
+    let mut reader = fs.open(path)?;
+    let mut hasher = SipHasher13::new();
+    let mut buf = vec![0u8; FULL_HASH_BLOCK_SIZE];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.write(&buf[..n]);
     }
+    Ok(hash128_to_u128(hasher.finish128()))
+}
 
-    // copy parallel
-    // let files = from_tos.iter().try_fold(0, |acc, (from, to)| {
-    //     if to.exists() {
-    //         log::debug!("skipped existing file {}", to.display());
-    //         return Ok(acc);
-    //     } else if let Some(p) = to.parent().filter(|p| !p.exists()) {
-    //         log::info!("creating directories {} for {}", p.display(), to.display());
-    //         create_dir_all(p)?;
-    //     }
-
-    //     log::trace!("copying from `{}` to `{}`", from.display(), to.display());
-    //     copy(from, to).map(|_| acc + 1)
-    // })?;
-    from_tos.par_iter().try_for_each(|(from, to)| {
-        if to.exists() {
-            log::warn!("skipped existing file {}", to.display());
-            return Ok(());
-        } else if let Some(p) = to.parent().filter(|p| !p.exists()) {
-            log::debug!("creating directories {} for {}", p.display(), to.display());
-            create_dir_all(p)?;
-        }
-        log::trace!("copying from `{}` to `{}`", from.display(), to.display());
-        copy(from, to).map(|_| ())
-    })?;
-    Ok(())
+fn sip_hash128(data: &[u8]) -> u128 {
+    let mut hasher = SipHasher13::new();
+    hasher.write(data);
+    hash128_to_u128(hasher.finish128())
 }
 
-fn walk_items(path: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
-    WalkDir::new(path)
+fn hash128_to_u128(hash: siphasher::sip128::Hash128) -> u128 {
+    ((hash.h1 as u128) << 64) | hash.h2 as u128
+}
+
+/// Decides, during a walk, which entries to keep.
+///
+/// `excludes`/`includes` are matched against the path relative to `root`, not
+/// the absolute path: a plain-name glob like `target` or `.git` would
+/// otherwise never match, since the absolute path never equals just that
+/// name. `gitignores` is one matcher per `.gitignore` file found under
+/// `root`, each rooted at that file's own parent directory so an anchored
+/// pattern (e.g. `/build` in `sub/.gitignore`) applies at `sub/`, not at
+/// `root` - entries are matched against those with the absolute path, since
+/// each matcher strips its own root internally.
+///
+/// Excludes and `.gitignore` rules prune whole directories (a match skips
+/// the entry and everything beneath it); includes only ever filter files,
+/// since a glob naming file patterns can't otherwise be reached through the
+/// directories that contain it.
+struct PathMatcher {
+    root: PathBuf,
+    excludes: GlobSet,
+    includes: GlobSet,
+    gitignores: Vec<Gitignore>,
+}
+
+impl PathMatcher {
+    fn new(root: PathBuf, excludes: GlobSet, includes: GlobSet, gitignores: Vec<Gitignore>) -> Self {
+        Self {
+            root,
+            excludes,
+            includes,
+            gitignores,
+        }
+    }
+
+    /// A matcher that keeps every entry.
+    #[cfg(test)]
+    fn permissive() -> Self {
+        Self::new(PathBuf::new(), GlobSet::empty(), GlobSet::empty(), vec![])
+    }
+
+    fn is_included(&self, path: &Path, is_dir: bool) -> bool {
+        let rel = path.strip_prefix(&self.root).unwrap_or(path);
+        if self.excludes.is_match(rel) {
+            return false;
+        }
+        if self
+            .gitignores
+            .iter()
+            .filter(|g| path.starts_with(g.path()))
+            .any(|g| g.matched(path, is_dir).is_ignore())
+        {
+            return false;
+        }
+        if is_dir {
+            return true;
+        }
+        if !self.includes.is_empty() && !self.includes.is_match(rel) {
+            return false;
+        }
+        true
+    }
+}
+
+fn build_globset(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build().map_err(Into::into)
+}
+
+/// Load every `.gitignore` file found anywhere under `root`, one matcher per
+/// file, each rooted at that file's own parent directory so its patterns
+/// (anchored or not) apply at the directory depth the file actually lives
+/// at rather than at `root`.
+fn load_gitignore(root: &Path) -> Vec<Gitignore> {
+    WalkDir::new(root)
         .into_iter()
-        .par_bridge()
-        .map(|dir| dir.map(|p| p.path().to_path_buf()))
-        .filter(|dir| dir.as_ref().map_or(true, |en| en.is_file()))
-        .collect::<Result<Vec<_>, _>>()
-        .map_err(Into::into)
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name() == ".gitignore")
+        .map(|entry| {
+            let (gitignore, err) = Gitignore::new(entry.path());
+            if let Some(e) = err {
+                log::warn!("failed to fully parse `{}`: {}", entry.path().display(), e);
+            }
+            gitignore
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -242,7 +775,8 @@ mod tests {
     use std::{
         env,
         fs::{create_dir, write},
-        sync::Once,
+        io::Cursor,
+        sync::{Mutex, Once},
     };
 
     static INIT: Once = Once::new();
@@ -310,10 +844,48 @@ mod tests {
         Ok(file_paths)
     }
 
+    #[test]
+    fn test_walk_excludes_prune_matched_directories() -> Result<()> {
+        let dir = tempdir()?;
+        create_dir(dir.path().join("target"))?;
+        write(dir.path().join("target").join("build.bin"), "binary")?;
+        create_dir(dir.path().join(".git"))?;
+        write(dir.path().join(".git").join("HEAD"), "ref: refs/heads/main")?;
+        write(dir.path().join("keep.txt"), "keep me")?;
+
+        let excludes = build_globset(&["target".to_string(), ".git".to_string()])?;
+        let matcher = PathMatcher::new(dir.path().to_path_buf(), excludes, GlobSet::empty(), vec![]);
+        let items = RealFs.walk(dir.path(), &matcher)?;
+
+        assert_eq!(items, vec![dir.path().join("keep.txt")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_scopes_nested_gitignore_to_its_own_directory() -> Result<()> {
+        let dir = tempdir()?;
+        create_dir(dir.path().join("sub"))?;
+        // anchored at `sub/`, so it must exclude only `sub/build`, not the
+        // unrelated top-level `build` directory
+        write(dir.path().join("sub").join(".gitignore"), "/build\n")?;
+        create_dir(dir.path().join("sub").join("build"))?;
+        write(dir.path().join("sub").join("build").join("artifact.bin"), "binary")?;
+        create_dir(dir.path().join("build"))?;
+        write(dir.path().join("build").join("keep.txt"), "keep me")?;
+
+        let gitignores = load_gitignore(dir.path());
+        let matcher = PathMatcher::new(dir.path().to_path_buf(), GlobSet::empty(), GlobSet::empty(), gitignores);
+        let items = RealFs.walk(dir.path(), &matcher)?;
+
+        assert!(!items.contains(&dir.path().join("sub").join("build").join("artifact.bin")));
+        assert!(items.contains(&dir.path().join("build").join("keep.txt")));
+        Ok(())
+    }
+
     #[test]
     fn test_walk() -> Result<()> {
         let (dir, file_paths) = &*TEMP_DIRS;
-        let items = walk_items(dir.path())?;
+        let items = RealFs.walk(dir.path(), &PathMatcher::permissive())?;
         assert_eq!(items.len(), file_paths.len());
         assert_eq!(
             items.iter().collect::<HashSet<_>>(),
@@ -327,9 +899,24 @@ mod tests {
         let to_dir = tempdir()?;
         let (from_dir, from_files) = &*TEMP_DIRS;
 
-        try_copy(&[from_dir.path()], &to_dir.path())?;
+        let cli = Cli {
+            from: vec![from_dir.path().to_path_buf()],
+            to: to_dir.path().to_path_buf(),
+            verbose: 0,
+            parallel_threads: None,
+            cron_expr: None,
+            update: false,
+            checksum: false,
+            dry_run: false,
+            print0: false,
+            excludes: vec![],
+            includes: vec![],
+            gitignore: false,
+            mirror: false,
+        };
+        try_copy(&RealFs, &cli)?;
 
-        let to_files = walk_items(to_dir.path())?;
+        let to_files = RealFs.walk(to_dir.path(), &PathMatcher::permissive())?;
         assert_eq!(from_files.len(), to_files.len());
 
         assert_eq!(
@@ -344,4 +931,307 @@ mod tests {
         );
         Ok(())
     }
+
+    fn cli_for(from: &[&str], to: &str) -> Cli {
+        Cli {
+            from: from.iter().map(PathBuf::from).collect(),
+            to: PathBuf::from(to),
+            verbose: 0,
+            parallel_threads: None,
+            cron_expr: None,
+            update: false,
+            checksum: false,
+            dry_run: false,
+            print0: false,
+            excludes: vec![],
+            includes: vec![],
+            gitignore: false,
+            mirror: false,
+        }
+    }
+
+    /// An in-memory filesystem used to exercise `try_copy` deterministically,
+    /// without spraying real temp files.
+    #[derive(Default)]
+    struct FakeFs {
+        files: Mutex<HashMap<PathBuf, Vec<u8>>>,
+        fail_copy_to: Mutex<HashSet<PathBuf>>,
+        fail_rename_to: Mutex<HashSet<PathBuf>>,
+    }
+
+    impl FakeFs {
+        fn new() -> Self {
+            Self::default()
+        }
+
+        fn with_file(self, path: impl Into<PathBuf>, content: impl Into<Vec<u8>>) -> Self {
+            self.files.lock().unwrap().insert(path.into(), content.into());
+            self
+        }
+
+        /// Make the next copy onto `path` fail, to exercise the atomic-rename
+        /// cleanup path without touching disk.
+        fn failing_copy_to(self, path: impl Into<PathBuf>) -> Self {
+            self.fail_copy_to.lock().unwrap().insert(path.into());
+            self
+        }
+
+        /// Make the next rename onto `path` fail, to exercise the cleanup
+        /// that runs after a failed rename, not just a failed copy.
+        fn failing_rename_to(self, path: impl Into<PathBuf>) -> Self {
+            self.fail_rename_to.lock().unwrap().insert(path.into());
+            self
+        }
+
+        fn contains(&self, path: impl AsRef<Path>) -> bool {
+            self.files.lock().unwrap().contains_key(path.as_ref())
+        }
+
+        /// Whether any leftover `.tmp-*` sibling is still present, i.e. a
+        /// failed copy didn't clean up after itself.
+        fn has_tmp_file(&self) -> bool {
+            self.files.lock().unwrap().keys().any(|p| {
+                p.file_name()
+                    .is_some_and(|n| n.to_string_lossy().contains(".tmp-"))
+            })
+        }
+    }
+
+    impl Fs for FakeFs {
+        fn walk(&self, root: &Path, matcher: &PathMatcher) -> Result<Vec<PathBuf>> {
+            Ok(self
+                .files
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|p| p.starts_with(root) && matcher.is_included(p, false))
+                .cloned()
+                .collect())
+        }
+
+        fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+            // Mirrors RealFs::copy's temp-file-then-rename so a simulated
+            // failure exercises the same leave-no-partial-file cleanup.
+            let tmp = tmp_path(to);
+            let content = self
+                .files
+                .lock()
+                .unwrap()
+                .get(from)
+                .cloned()
+                .ok_or_else(|| anyhow!("no such file: `{}`", from.display()))?;
+            self.files.lock().unwrap().insert(tmp.clone(), content);
+
+            if self.fail_copy_to.lock().unwrap().remove(to) {
+                self.files.lock().unwrap().remove(&tmp);
+                bail!("simulated mid-copy failure for `{}`", to.display());
+            }
+
+            if self.fail_rename_to.lock().unwrap().remove(to) {
+                self.files.lock().unwrap().remove(&tmp);
+                bail!("simulated rename failure for `{}`", to.display());
+            }
+
+            let content = self.files.lock().unwrap().remove(&tmp).unwrap();
+            self.files.lock().unwrap().insert(to.to_path_buf(), content);
+            Ok(())
+        }
+
+        fn create_dir_all(&self, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn metadata(&self, path: &Path) -> Result<Meta> {
+            let files = self.files.lock().unwrap();
+            let content = files
+                .get(path)
+                .ok_or_else(|| anyhow!("no such file: `{}`", path.display()))?;
+            Ok(Meta {
+                len: content.len() as u64,
+                modified: None,
+            })
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            self.contains(path)
+        }
+
+        fn remove_file(&self, path: &Path) -> Result<()> {
+            self.files
+                .lock()
+                .unwrap()
+                .remove(path)
+                .map(|_| ())
+                .ok_or_else(|| anyhow!("no such file: `{}`", path.display()))
+        }
+
+        fn open(&self, path: &Path) -> Result<Box<dyn Read>> {
+            let content = self
+                .files
+                .lock()
+                .unwrap()
+                .get(path)
+                .cloned()
+                .ok_or_else(|| anyhow!("no such file: `{}`", path.display()))?;
+            Ok(Box::new(Cursor::new(content)))
+        }
+
+        fn canonicalize(&self, path: &Path) -> Result<PathBuf> {
+            Ok(path.to_path_buf())
+        }
+    }
+
+    #[test]
+    fn test_try_copy_fake_fs() -> Result<()> {
+        let fs = FakeFs::new().with_file("/from/a.txt", "hello");
+        let cli = cli_for(&["/from"], "/to");
+
+        try_copy(&fs, &cli)?;
+
+        assert!(fs.contains("/to/a.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_copy_missing_from_yields_no_items() -> Result<()> {
+        let fs = FakeFs::new();
+        let cli = cli_for(&["/missing"], "/to");
+
+        // a `from` root with no matching files copies nothing, not a hard error
+        try_copy(&fs, &cli)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_files_differ_checksum_same_length_differing_first_block() -> Result<()> {
+        let fs = FakeFs::new()
+            .with_file("/from/a.txt", "hello")
+            .with_file("/to/a.txt", "world");
+        assert!(files_differ(
+            &fs,
+            Path::new("/from/a.txt"),
+            Path::new("/to/a.txt"),
+            CompareMode::Checksum
+        )?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_files_differ_checksum_same_first_block_differing_tail() -> Result<()> {
+        let from_content = vec![b'a'; PARTIAL_HASH_SIZE + 10];
+        let mut to_content = from_content.clone();
+        // flip a byte past the partial-hash window so only the full hash catches it
+        *to_content.last_mut().unwrap() = b'b';
+        let fs = FakeFs::new()
+            .with_file("/from/a.txt", from_content)
+            .with_file("/to/a.txt", to_content);
+        assert!(files_differ(
+            &fs,
+            Path::new("/from/a.txt"),
+            Path::new("/to/a.txt"),
+            CompareMode::Checksum
+        )?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_files_differ_checksum_identical_content_is_not_different() -> Result<()> {
+        let fs = FakeFs::new()
+            .with_file("/from/a.txt", "identical")
+            .with_file("/to/a.txt", "identical");
+        assert!(!files_differ(
+            &fs,
+            Path::new("/from/a.txt"),
+            Path::new("/to/a.txt"),
+            CompareMode::Checksum
+        )?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_copy_checksum_skips_byte_identical_files() -> Result<()> {
+        let fs = FakeFs::new()
+            .with_file("/from/a.txt", "same content")
+            .with_file("/to/a.txt", "same content")
+            .failing_copy_to(PathBuf::from("/to/a.txt"));
+        let mut cli = cli_for(&["/from"], "/to");
+        cli.checksum = true;
+
+        // identical content must be caught by the hash comparison without
+        // calling `copy`, which would hit the simulated failure above.
+        try_copy(&fs, &cli)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_copy_mirror_deletes_orphaned_destination_file() -> Result<()> {
+        let fs = FakeFs::new()
+            .with_file("/from/a.txt", "a")
+            .with_file("/to/a.txt", "a")
+            .with_file("/to/orphan.txt", "stale");
+        let mut cli = cli_for(&["/from"], "/to");
+        cli.mirror = true;
+
+        try_copy(&fs, &cli)?;
+
+        assert!(!fs.contains("/to/orphan.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_copy_mirror_keeps_destination_file_with_surviving_source() -> Result<()> {
+        let fs = FakeFs::new()
+            .with_file("/from/keep.txt", "keep")
+            .with_file("/to/keep.txt", "keep");
+        let mut cli = cli_for(&["/from"], "/to");
+        cli.mirror = true;
+
+        try_copy(&fs, &cli)?;
+
+        assert!(fs.contains("/to/keep.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_copy_mirror_never_deletes_excluded_destination_path() -> Result<()> {
+        let fs = FakeFs::new()
+            .with_file("/from/a.txt", "a")
+            .with_file("/to/a.txt", "a")
+            .with_file("/to/cache/orphan.txt", "stale");
+        let mut cli = cli_for(&["/from"], "/to");
+        cli.mirror = true;
+        cli.excludes = vec!["cache/**".to_string()];
+
+        try_copy(&fs, &cli)?;
+
+        assert!(fs.contains("/to/cache/orphan.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_copy_mid_copy_failure_is_reported() {
+        let fs = FakeFs::new()
+            .with_file("/from/a.txt", "hello")
+            .failing_copy_to(PathBuf::from("/to/a.txt"));
+        let cli = cli_for(&["/from"], "/to");
+
+        assert!(try_copy(&fs, &cli).is_err());
+        assert!(!fs.contains("/to/a.txt"));
+        // the atomic-rename temp sibling must be cleaned up, not just `to`
+        assert!(!fs.has_tmp_file());
+    }
+
+    #[test]
+    fn test_try_copy_mid_rename_failure_is_reported() {
+        let fs = FakeFs::new()
+            .with_file("/from/a.txt", "hello")
+            .failing_rename_to(PathBuf::from("/to/a.txt"));
+        let cli = cli_for(&["/from"], "/to");
+
+        assert!(try_copy(&fs, &cli).is_err());
+        assert!(!fs.contains("/to/a.txt"));
+        // a failed rename must clean up its temp sibling too, not just a
+        // failed copy
+        assert!(!fs.has_tmp_file());
+    }
 }